@@ -1,16 +1,54 @@
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Write};
 
-pub struct GraphStack<T> {
+/// Errors returned when mutating a GraphStack would leave it inconsistent.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GraphStackError {
+    /// An ancestor id doesn't refer to an already-inserted item.
+    InvalidAncestor(usize),
+    /// The item id being referenced doesn't exist.
+    InvalidItem(usize),
+    /// Adding edge `id -> ancestor` would close a cycle.
+    Cycle { id: usize, ancestor: usize },
+}
+
+impl fmt::Display for GraphStackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GraphStackError::InvalidAncestor(a) => write!(f, "invalid ancestor id={}", a),
+            GraphStackError::InvalidItem(id) => write!(f, "invalid item id={}", id),
+            GraphStackError::Cycle { id, ancestor } => {
+                write!(f, "edge {}->{} would create a cycle", id, ancestor)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GraphStackError {}
+
+/// Encodes and decodes a graph-stack element to and from bytes, so the
+/// snapshot format stays independent of any particular serialization crate.
+pub trait ElementCodec<T> {
+    /// Encode `value` into a self-delimited-by-caller byte buffer.
+    fn encode(&self, value: &T) -> Vec<u8>;
+    /// Decode a value from the bytes produced by [`ElementCodec::encode`].
+    fn decode(&self, bytes: &[u8]) -> io::Result<T>;
+}
+
+pub struct GraphStack<T, L = ()> {
     /// Holds the elements inserted into the GraphStack.
     items: Vec<T>,
 
     /// In a stack, each element sits on top of another.
     /// In a graph-stack, each item may have multiple ancestors.
-    /// The indexes in `ancestors` direclty map to items in `items`.
-    ancestors: HashMap<usize, Vec<usize>>,
+    /// Each edge records the ancestor's index into `items` together with an
+    /// optional per-edge label `L` (e.g. the grammar symbol consumed to reach
+    /// it). `L` defaults to `()` for plain, unlabeled graph-stacks.
+    ancestors: HashMap<usize, Vec<(usize, L)>>,
 }
 
-impl<T> GraphStack<T> {
+impl<T, L> GraphStack<T, L> {
     pub fn new() -> Self {
         GraphStack {
             items: Vec::new(),
@@ -18,36 +56,630 @@ impl<T> GraphStack<T> {
         }
     }
 
+    /// Returns whether `to` is reachable from `from` by following ancestors.
+    pub fn has_path(&self, from: usize, to: usize) -> bool {
+        let mut visited = vec![false; self.items.len()];
+        self.reaches(from, to, &mut visited)
+    }
+
+    /// DFS over the `ancestors` map from `from`, reporting whether `to` is
+    /// reached. `visited` is cleared on entry so it can be reused by callers.
+    fn reaches(&self, from: usize, to: usize, visited: &mut [bool]) -> bool {
+        for v in visited.iter_mut() {
+            *v = false;
+        }
+        let mut stack = vec![from];
+        while let Some(node) = stack.pop() {
+            if node == to {
+                return true;
+            }
+            if visited[node] {
+                continue;
+            }
+            visited[node] = true;
+            if let Some(parents) = self.ancestors.get(&node) {
+                stack.extend(parents.iter().map(|&(a, _)| a));
+            }
+        }
+        false
+    }
+
+    /// Collapse every strongly-connected component into a single node and
+    /// return the condensation DAG. Each node of the result carries the set
+    /// of original item-ids that formed one SCC, and edges between distinct
+    /// components are preserved (deduplicated). Computed with an iterative
+    /// Tarjan's algorithm over the `ancestors` adjacency.
+    pub fn condense(&self) -> GraphStack<Vec<usize>> {
+        let n = self.items.len();
+        let mut index = vec![usize::MAX; n]; // discovery order; MAX == unvisited
+        let mut lowlink = vec![0usize; n];
+        let mut on_stack = vec![false; n];
+        let mut tstack: Vec<usize> = Vec::new(); // Tarjan's component stack
+        let mut comp_of = vec![usize::MAX; n];
+        let mut components: Vec<Vec<usize>> = Vec::new();
+        let mut next_index = 0usize;
+
+        // Explicit DFS stack of (node, next successor offset) to stay O(V+E)
+        // without risking a recursion overflow on deep graphs.
+        for root in 0..n {
+            if index[root] != usize::MAX {
+                continue;
+            }
+            index[root] = next_index;
+            lowlink[root] = next_index;
+            next_index += 1;
+            tstack.push(root);
+            on_stack[root] = true;
+            let mut dfs = vec![(root, 0usize)];
+            while !dfs.is_empty() {
+                let top = dfs.len() - 1;
+                let (v, pos) = dfs[top];
+                let succs = match self.ancestors.get(&v) {
+                    Some(s) => s.as_slice(),
+                    None => &[],
+                };
+                if pos < succs.len() {
+                    dfs[top].1 += 1;
+                    let w = succs[pos].0;
+                    if index[w] == usize::MAX {
+                        index[w] = next_index;
+                        lowlink[w] = next_index;
+                        next_index += 1;
+                        tstack.push(w);
+                        on_stack[w] = true;
+                        dfs.push((w, 0));
+                    } else if on_stack[w] && index[w] < lowlink[v] {
+                        lowlink[v] = index[w];
+                    }
+                } else {
+                    if lowlink[v] == index[v] {
+                        let mut comp = Vec::new();
+                        loop {
+                            let w = tstack.pop().unwrap();
+                            on_stack[w] = false;
+                            comp_of[w] = components.len();
+                            comp.push(w);
+                            if w == v {
+                                break;
+                            }
+                        }
+                        components.push(comp);
+                    }
+                    dfs.pop();
+                    if let Some(&(parent, _)) = dfs.last() {
+                        if lowlink[v] < lowlink[parent] {
+                            lowlink[parent] = lowlink[v];
+                        }
+                    }
+                }
+            }
+        }
+
+        // Tarjan emits components in reverse topological order, so every
+        // successor component already has a smaller id and is pushed first.
+        let mut condensed = GraphStack::new();
+        for (c, members) in components.iter().enumerate() {
+            let mut anc: Vec<usize> = Vec::new();
+            for &m in members {
+                if let Some(parents) = self.ancestors.get(&m) {
+                    for &(p, _) in parents {
+                        let pc = comp_of[p];
+                        if pc != c && !anc.contains(&pc) {
+                            anc.push(pc);
+                        }
+                    }
+                }
+            }
+            anc.sort_unstable();
+            condensed
+                .push(members.clone(), &anc)
+                .expect("condensation is acyclic by construction");
+        }
+        condensed
+    }
+
+    /// Return the item-ids that are ancestors of `target` but are neither
+    /// ancestors of, nor equal to, any item in `bases`, in ascending order.
+    /// A branch is pruned as soon as it enters the bases' ancestor closure,
+    /// so shared prefixes are walked only once.
+    pub fn missing_ancestors(&self, bases: &[usize], target: usize) -> Vec<usize> {
+        // Closed ancestor set of the bases, including the bases themselves.
+        let mut base_closure: HashSet<usize> = HashSet::new();
+        let mut work: Vec<usize> = bases.to_vec();
+        while let Some(n) = work.pop() {
+            if base_closure.insert(n) {
+                if let Some(parents) = self.ancestors.get(&n) {
+                    work.extend(parents.iter().map(|&(a, _)| a));
+                }
+            }
+        }
+        // Walk the ancestor closure of `target`, skipping covered branches.
+        let mut result: HashSet<usize> = HashSet::new();
+        let mut work: Vec<usize> = self
+            .ancestors
+            .get(&target)
+            .map(|parents| parents.iter().map(|&(a, _)| a).collect())
+            .unwrap_or_default();
+        while let Some(n) = work.pop() {
+            if base_closure.contains(&n) {
+                continue;
+            }
+            if result.insert(n) {
+                if let Some(parents) = self.ancestors.get(&n) {
+                    work.extend(parents.iter().map(|&(a, _)| a));
+                }
+            }
+        }
+        let mut out: Vec<usize> = result.into_iter().collect();
+        out.sort_unstable();
+        out
+    }
+
+    /// Immediate dominators of every item reachable from `start_item` over the
+    /// `ancestors`-DAG: `idom[v]` is the single item that every path from
+    /// `start_item` down to `v` must pass through. Computed with the iterative
+    /// Cooper–Harvey–Kennedy algorithm. `idom[start_item] == start_item`.
+    pub fn dominators(&self, start_item: usize) -> HashMap<usize, usize> {
+        self.dominators_with_rpo(start_item).0
+    }
+
+    /// Nearest common ancestor of `a` and `b` in the dominator tree rooted at
+    /// `start_item` — the deepest item every path from `start_item` to either
+    /// of them must cross. Returns `None` if `a` or `b` isn't reachable.
+    pub fn nearest_common_ancestor(
+        &self,
+        start_item: usize,
+        a: usize,
+        b: usize,
+    ) -> Option<usize> {
+        let (idom, rpo) = self.dominators_with_rpo(start_item);
+        if !idom.contains_key(&a) || !idom.contains_key(&b) {
+            return None;
+        }
+        Some(Self::intersect(a, b, &idom, &rpo))
+    }
+
+    /// Shared core: returns the immediate-dominator map plus the
+    /// reverse-postorder numbering the two-finger `intersect` relies on.
+    fn dominators_with_rpo(
+        &self,
+        start_item: usize,
+    ) -> (HashMap<usize, usize>, HashMap<usize, usize>) {
+        // Iterative DFS postorder over nodes reachable via `ancestors`.
+        let mut visited: HashSet<usize> = HashSet::new();
+        let mut postorder: Vec<usize> = Vec::new();
+        let mut stack: Vec<(usize, usize)> = vec![(start_item, 0)];
+        visited.insert(start_item);
+        while let Some(&(node, pos)) = stack.last() {
+            let succs = match self.ancestors.get(&node) {
+                Some(s) => s.as_slice(),
+                None => &[],
+            };
+            if pos < succs.len() {
+                stack.last_mut().unwrap().1 += 1;
+                let w = succs[pos].0;
+                if visited.insert(w) {
+                    stack.push((w, 0));
+                }
+            } else {
+                postorder.push(node);
+                stack.pop();
+            }
+        }
+
+        // Reverse postorder: entry first, so predecessors are seen early.
+        let rpo: Vec<usize> = postorder.iter().rev().copied().collect();
+        let mut rpo_num: HashMap<usize, usize> = HashMap::new();
+        for (i, &n) in rpo.iter().enumerate() {
+            rpo_num.insert(n, i);
+        }
+
+        // Predecessors among reachable nodes: edge `u -> v` makes `u` a pred.
+        let mut preds: HashMap<usize, Vec<usize>> = HashMap::new();
+        for &u in &rpo {
+            if let Some(parents) = self.ancestors.get(&u) {
+                for &(v, _) in parents {
+                    if rpo_num.contains_key(&v) {
+                        preds.entry(v).or_default().push(u);
+                    }
+                }
+            }
+        }
+
+        let mut idom: HashMap<usize, usize> = HashMap::new();
+        idom.insert(start_item, start_item);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &n in &rpo {
+                if n == start_item {
+                    continue;
+                }
+                let mut new_idom: Option<usize> = None;
+                if let Some(ps) = preds.get(&n) {
+                    for &p in ps {
+                        if idom.contains_key(&p) {
+                            new_idom = Some(match new_idom {
+                                None => p,
+                                Some(ni) => Self::intersect(p, ni, &idom, &rpo_num),
+                            });
+                        }
+                    }
+                }
+                if let Some(ni) = new_idom {
+                    if idom.get(&n) != Some(&ni) {
+                        idom.insert(n, ni);
+                        changed = true;
+                    }
+                }
+            }
+        }
+        (idom, rpo_num)
+    }
+
+    /// Two-finger walk up the idom chains: advance whichever finger has the
+    /// higher reverse-postorder number (is further from the entry) until the
+    /// two pointers meet at their common dominator.
+    fn intersect(
+        mut a: usize,
+        mut b: usize,
+        idom: &HashMap<usize, usize>,
+        rpo: &HashMap<usize, usize>,
+    ) -> usize {
+        while a != b {
+            while rpo[&a] > rpo[&b] {
+                a = idom[&a];
+            }
+            while rpo[&b] > rpo[&a] {
+                b = idom[&b];
+            }
+        }
+        a
+    }
+
+    /// Build an iterator over the stacks encoded by this GraphStack.
+    /// A `start_item` is required because there may be multiple top items.
+    /// Edge labels are ignored; see [`GraphStack::labeled_stacks`] to see them.
+    pub fn stacks(&self, start_item: usize) -> Stacks<T, L> {
+        Stacks::new(&self, start_item)
+    }
+
+    /// Like [`GraphStack::stacks`] but each item is paired with the label of
+    /// the edge traversed to reach it from the previous item. The first item
+    /// of every stack carries `None`, having no incoming edge.
+    pub fn labeled_stacks(&self, start_item: usize) -> LabeledStacks<T, L> {
+        LabeledStacks::new(self, start_item)
+    }
+
+    /// Lazily enumerate the *set* of ancestor item-ids reachable from any of
+    /// `starts`, yielding each distinct id once. The order is descending while
+    /// every ancestor id is smaller than the id of the item referencing it, as
+    /// for edges given to `push`; an `add_ancestors` edge to a higher id can
+    /// make a larger id surface after a smaller one. Ids below
+    /// `stop` are never traversed, and with `inclusive=false` the `starts`
+    /// themselves are expanded but not emitted. Cheaper than `stacks()` when
+    /// only reachability (not the paths) matters.
+    pub fn ancestors(&self, starts: &[usize], stop: usize, inclusive: bool) -> Ancestors<T, L> {
+        Ancestors::new(self, starts, stop, inclusive)
+    }
+}
+
+impl<T, L: Default> GraphStack<T, L> {
     /// Adds an element to the graph-stack and returns an item-id for it.
-    /// This `id` can later be used to add ancestors for this item.
-    pub fn push(&mut self, value: T, ancestors: &[usize]) -> usize {
+    /// This `id` can later be used to add ancestors for this item. Each edge
+    /// is stored with the default label; use [`GraphStack::push_labeled`] to
+    /// supply labels. Fails if any ancestor doesn't refer to an inserted item.
+    pub fn push(&mut self, value: T, ancestors: &[usize]) -> Result<usize, GraphStackError> {
         // Check that each ancestor is valid
-        if ancestors.iter().any(|a| *a >= self.items.len()) {
-            panic!(
-                "Invalid ancestors. GS size={}, ancestors={:#?}",
-                self.items.len(),
-                ancestors
-            );
+        if let Some(&a) = ancestors.iter().find(|a| **a >= self.items.len()) {
+            return Err(GraphStackError::InvalidAncestor(a));
         }
         self.items.push(value);
         let item_id = self.items.len() - 1;
+        self.ancestors.insert(
+            item_id,
+            ancestors.iter().map(|&a| (a, L::default())).collect(),
+        );
+        Ok(item_id)
+    }
+
+    /// Adds extra ancestors to an existing item, each with the default label.
+    /// Rejects the whole batch (leaving the structure unchanged) if any
+    /// ancestor is invalid or would close a cycle. Since ancestors only
+    /// point at already-inserted items, an edge `id -> a` closes a cycle
+    /// exactly when `id` is already reachable from `a`.
+    pub fn add_ancestors(&mut self, id: usize, ancestors: &[usize]) -> Result<(), GraphStackError> {
+        if !self.ancestors.contains_key(&id) {
+            return Err(GraphStackError::InvalidItem(id));
+        }
+        // Reuse one scratch buffer across the reachability probes.
+        let mut visited = vec![false; self.items.len()];
+        for &a in ancestors {
+            if a >= self.items.len() {
+                return Err(GraphStackError::InvalidAncestor(a));
+            }
+            if self.reaches(a, id, &mut visited) {
+                return Err(GraphStackError::Cycle { id, ancestor: a });
+            }
+        }
         self.ancestors
-            .insert(item_id, ancestors.iter().cloned().collect());
-        item_id
+            .entry(id)
+            .or_default()
+            .extend(ancestors.iter().map(|&a| (a, L::default())));
+        Ok(())
+    }
+}
+
+impl<T, L: Clone> GraphStack<T, L> {
+    /// Like [`GraphStack::push`] but each ancestor edge carries an explicit
+    /// label, the way a GSS edge records the symbol consumed to reach it.
+    pub fn push_labeled(
+        &mut self,
+        value: T,
+        ancestors: &[(usize, L)],
+    ) -> Result<usize, GraphStackError> {
+        if let Some(&(a, _)) = ancestors.iter().find(|(a, _)| *a >= self.items.len()) {
+            return Err(GraphStackError::InvalidAncestor(a));
+        }
+        self.items.push(value);
+        let item_id = self.items.len() - 1;
+        self.ancestors.insert(item_id, ancestors.to_vec());
+        Ok(item_id)
     }
 
-    pub fn add_ancestors(&mut self, id: usize, ancestors: &[usize]) {
+    /// Like [`GraphStack::add_ancestors`] but each new edge carries a label.
+    /// Rejects the whole batch if any ancestor is invalid or closes a cycle.
+    pub fn add_ancestors_labeled(
+        &mut self,
+        id: usize,
+        ancestors: &[(usize, L)],
+    ) -> Result<(), GraphStackError> {
         if !self.ancestors.contains_key(&id) {
-            panic!("Invalid ancestor id={}", id);
+            return Err(GraphStackError::InvalidItem(id));
         }
-        // TODO: detect cycles
-        self.ancestors.entry(id).or_default().extend(ancestors);
+        let mut visited = vec![false; self.items.len()];
+        for (a, _) in ancestors {
+            if *a >= self.items.len() {
+                return Err(GraphStackError::InvalidAncestor(*a));
+            }
+            if self.reaches(*a, id, &mut visited) {
+                return Err(GraphStackError::Cycle { id, ancestor: *a });
+            }
+        }
+        self.ancestors
+            .entry(id)
+            .or_default()
+            .extend(ancestors.iter().cloned());
+        Ok(())
     }
+}
 
-    /// Build an iterator over the stacks encoded by this GraphStack.
-    /// A `start_item` is required because there may be multiple top items.
-    pub fn stacks(&self, start_item: usize) -> Stacks<T> {
-        Stacks::new(&self, start_item)
+/// A lazy iterator over the distinct ancestor item-ids of one or more items,
+/// produced in descending id order. See [`GraphStack::ancestors`].
+pub struct Ancestors<'a, T, L = ()> {
+    gs: &'a GraphStack<T, L>,
+    /// Max-heap so the largest outstanding id surfaces next.
+    heap: BinaryHeap<usize>,
+    /// Ids already enqueued, so each is emitted exactly once.
+    seen: HashSet<usize>,
+    /// Ancestors below this id are pruned.
+    stop: usize,
+}
+
+impl<'a, T, L> Ancestors<'a, T, L> {
+    fn new(gs: &'a GraphStack<T, L>, starts: &[usize], stop: usize, inclusive: bool) -> Self {
+        let mut it = Ancestors {
+            gs,
+            heap: BinaryHeap::new(),
+            seen: HashSet::new(),
+            stop,
+        };
+        if inclusive {
+            for &s in starts {
+                if it.seen.insert(s) {
+                    it.heap.push(s);
+                }
+            }
+        } else {
+            // Mark the starts seen so they're never emitted, but still expand
+            // them to seed the traversal with their ancestors.
+            for &s in starts {
+                it.seen.insert(s);
+            }
+            for &s in starts {
+                it.enqueue_ancestors(s);
+            }
+        }
+        it
+    }
+
+    /// Enqueue the not-yet-seen ancestors of `id` that clear the `stop` bound.
+    fn enqueue_ancestors(&mut self, id: usize) {
+        if let Some(parents) = self.gs.ancestors.get(&id) {
+            for &(p, _) in parents {
+                if p >= self.stop && self.seen.insert(p) {
+                    self.heap.push(p);
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T, L> Iterator for Ancestors<'a, T, L> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        // `seen` keeps each id to a single emission. When ancestor ids are
+        // smaller than the ids referencing them, popping the maximum also
+        // means every id that could enqueue this one was processed first.
+        let id = self.heap.pop()?;
+        self.enqueue_ancestors(id);
+        Some(id)
+    }
+}
+
+impl<T, L> GraphStack<T, L> {
+    /// Write a compact little-endian snapshot of the items and the ancestor
+    /// adjacency (labels are not persisted). The stream starts with the 32-byte
+    /// content id, followed by a `u64` item count, then per item a `u32`
+    /// length-prefixed encoded value, a varint ancestor count, and the
+    /// ancestor ids as varints. Returns the content id that was written.
+    pub fn serialize<W, C>(&self, w: &mut W, codec: &C) -> io::Result<[u8; 32]>
+    where
+        W: Write,
+        C: ElementCodec<T>,
+    {
+        let digest = self.content_id(codec);
+        w.write_all(&digest)?;
+        w.write_all(&(self.items.len() as u64).to_le_bytes())?;
+        for id in 0..self.items.len() {
+            let bytes = codec.encode(&self.items[id]);
+            w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            w.write_all(&bytes)?;
+            let parents = self.ancestors.get(&id);
+            write_varint(w, parents.map(|p| p.len()).unwrap_or(0) as u64)?;
+            if let Some(parents) = parents {
+                for &(a, _) in parents {
+                    write_varint(w, a as u64)?;
+                }
+            }
+        }
+        Ok(digest)
+    }
+
+    /// A 32-byte content id folding each item's id, encoded bytes and sorted
+    /// ancestor ids. Two structurally identical graph-stacks hash equally
+    /// regardless of the order ancestors were added, enabling dedup and cheap
+    /// equality checks across reloads.
+    pub fn content_id<C: ElementCodec<T>>(&self, codec: &C) -> [u8; 32] {
+        // Four FNV-1a lanes seeded apart so the digest spans the full 256 bits.
+        let mut state: [u64; 4] = [
+            0xcbf29ce484222325,
+            0x9e3779b97f4a7c15,
+            0x3c6ef372fe94f82b,
+            0x1c0ffee5ca11ab1e,
+        ];
+        for id in 0..self.items.len() {
+            fold(&mut state, &(id as u64).to_le_bytes());
+            let bytes = codec.encode(&self.items[id]);
+            fold(&mut state, &(bytes.len() as u64).to_le_bytes());
+            fold(&mut state, &bytes);
+            let mut anc: Vec<usize> = self
+                .ancestors
+                .get(&id)
+                .map(|p| p.iter().map(|&(a, _)| a).collect())
+                .unwrap_or_default();
+            anc.sort_unstable();
+            fold(&mut state, &(anc.len() as u64).to_le_bytes());
+            for a in anc {
+                fold(&mut state, &(a as u64).to_le_bytes());
+            }
+        }
+        let mut out = [0u8; 32];
+        for (i, lane) in state.iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&lane.to_le_bytes());
+        }
+        out
+    }
+}
+
+impl<T> GraphStack<T> {
+    /// Reconstruct a graph-stack from the format produced by
+    /// [`GraphStack::serialize`], verifying that the recomputed content id
+    /// matches the one stored in the stream.
+    pub fn deserialize<R, C>(r: &mut R, codec: &C) -> io::Result<GraphStack<T>>
+    where
+        R: Read,
+        C: ElementCodec<T>,
+    {
+        let mut digest = [0u8; 32];
+        r.read_exact(&mut digest)?;
+        let mut count_buf = [0u8; 8];
+        r.read_exact(&mut count_buf)?;
+        let count = u64::from_le_bytes(count_buf) as usize;
+
+        let mut gs: GraphStack<T> = GraphStack::new();
+        let mut edges: Vec<Vec<usize>> = Vec::new();
+        for _ in 0..count {
+            let mut len_buf = [0u8; 4];
+            r.read_exact(&mut len_buf)?;
+            let len = u32::from_le_bytes(len_buf) as usize;
+            let mut value_bytes = vec![0u8; len];
+            r.read_exact(&mut value_bytes)?;
+            let value = codec.decode(&value_bytes)?;
+            let n = read_varint(r)? as usize;
+            let mut parents = Vec::with_capacity(n);
+            for _ in 0..n {
+                parents.push(read_varint(r)? as usize);
+            }
+            gs.push(value, &[])
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            edges.push(parents);
+        }
+        // Edges are added once every item exists, since `add_ancestors` may
+        // have linked an item to an ancestor with a higher id.
+        for (id, parents) in edges.iter().enumerate() {
+            gs.add_ancestors(id, parents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+
+        if gs.content_id(codec) != digest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "content digest mismatch",
+            ));
+        }
+        Ok(gs)
+    }
+}
+
+/// Fold `bytes` into each digest lane FNV-1a style (xor each byte, then
+/// multiply), with a distinct odd multiplier per lane.
+fn fold(state: &mut [u64; 4], bytes: &[u8]) {
+    const PRIMES: [u64; 4] = [
+        0x100000001b3,
+        0x1000193,
+        0xff51afd7ed558ccd,
+        0xc4ceb9fe1a85ec53,
+    ];
+    for (lane, prime) in state.iter_mut().zip(PRIMES) {
+        for &b in bytes {
+            *lane ^= b as u64;
+            *lane = lane.wrapping_mul(prime);
+        }
+    }
+}
+
+/// Write `v` as an unsigned LEB128 varint.
+fn write_varint<W: Write>(w: &mut W, mut v: u64) -> io::Result<()> {
+    loop {
+        let mut byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v != 0 {
+            byte |= 0x80;
+        }
+        w.write_all(&[byte])?;
+        if v == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Read an unsigned LEB128 varint written by [`write_varint`].
+fn read_varint<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut buf = [0u8; 1];
+        r.read_exact(&mut buf)?;
+        result |= ((buf[0] & 0x7f) as u64) << shift;
+        if buf[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "varint too long"));
+        }
     }
 }
 
@@ -59,7 +691,7 @@ struct Cursor {
 }
 
 /// An iterator to retrieve stacks encoded in GraphStack.
-pub struct Stacks<'a, T> {
+pub struct Stacks<'a, T, L = ()> {
     /// Need a cursor for each item in the GraphStack to track
     /// which of its ancestors is currently being traversed.
     cursors: Vec<Cursor>,
@@ -68,11 +700,11 @@ pub struct Stacks<'a, T> {
     unstack: Vec<&'a T>,
 
     /// A reference to the GraphStack that this iterator is traversing.
-    gs: &'a GraphStack<T>,
+    gs: &'a GraphStack<T, L>,
 }
 
-impl<'a, T> Stacks<'a, T> {
-    fn new(gs: &'a GraphStack<T>, start_item: usize) -> Self {
+impl<'a, T, L> Stacks<'a, T, L> {
+    fn new(gs: &'a GraphStack<T, L>, start_item: usize) -> Self {
         Stacks {
             cursors: vec![Cursor {
                 item: start_item,
@@ -84,7 +716,7 @@ impl<'a, T> Stacks<'a, T> {
     }
 }
 
-impl<'a, T> Iterator for Stacks<'a, T> {
+impl<'a, T, L> Iterator for Stacks<'a, T, L> {
     type Item = Vec<&'a T>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -99,7 +731,7 @@ impl<'a, T> Iterator for Stacks<'a, T> {
             if item_ancestors.is_empty() {
                 break;
             }
-            let prev_item_id = item_ancestors[cursor.ancestor];
+            let (prev_item_id, _) = item_ancestors[cursor.ancestor];
             self.unstack.push(&self.gs.items[prev_item_id]);
             self.cursors.push(Cursor {
                 item: prev_item_id,
@@ -124,29 +756,115 @@ impl<'a, T> Iterator for Stacks<'a, T> {
     }
 }
 
+/// An iterator like [`Stacks`] that also exposes the label of each traversed
+/// edge, so consumers see both the node sequence and the edges between them.
+pub struct LabeledStacks<'a, T, L> {
+    cursors: Vec<Cursor>,
+    /// Each entry pairs an item with the label of its incoming edge, if any.
+    unstack: Vec<(&'a T, Option<&'a L>)>,
+    gs: &'a GraphStack<T, L>,
+}
+
+impl<'a, T, L> LabeledStacks<'a, T, L> {
+    fn new(gs: &'a GraphStack<T, L>, start_item: usize) -> Self {
+        LabeledStacks {
+            cursors: vec![Cursor {
+                item: start_item,
+                ancestor: 0,
+            }],
+            // The start item has no incoming edge.
+            unstack: vec![(&gs.items[start_item], None)],
+            gs,
+        }
+    }
+}
+
+impl<'a, T, L> Iterator for LabeledStacks<'a, T, L> {
+    type Item = Vec<(&'a T, Option<&'a L>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cursors.is_empty() {
+            return None;
+        }
+
+        // Build a snapshot of the stack pointed to by current cursors
+        while let Some(cursor) = self.cursors.last() {
+            let ref item_ancestors = self.gs.ancestors[&cursor.item];
+            if item_ancestors.is_empty() {
+                break;
+            }
+            let (prev_item_id, ref label) = item_ancestors[cursor.ancestor];
+            self.unstack
+                .push((&self.gs.items[prev_item_id], Some(label)));
+            self.cursors.push(Cursor {
+                item: prev_item_id,
+                ancestor: 0,
+            });
+        }
+        let stack_snapshot = self.unstack.clone();
+
+        // Advance iterator: find the cursor to advance depth-first
+        while let Some(cursor) = self.cursors.last_mut() {
+            let num_item_ancestors = self.gs.ancestors[&cursor.item].len();
+            if cursor.ancestor + 1 < num_item_ancestors {
+                cursor.ancestor += 1;
+                break;
+            }
+            self.cursors.pop();
+        }
+        self.unstack.truncate(self.cursors.len());
+
+        Some(stack_snapshot)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::GraphStack;
+    use super::{ElementCodec, GraphStack, GraphStackError};
     use std::collections::HashMap;
+    use std::io;
+
+    /// A minimal codec storing strings as their raw UTF-8 bytes.
+    struct Utf8Codec;
+    impl ElementCodec<String> for Utf8Codec {
+        fn encode(&self, value: &String) -> Vec<u8> {
+            value.as_bytes().to_vec()
+        }
+        fn decode(&self, bytes: &[u8]) -> io::Result<String> {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    fn sample() -> GraphStack<String> {
+        // a - b - c
+        //      \ d -/  (c has ancestors b and d)
+        let mut gs: GraphStack<String> = GraphStack::new();
+        let a = gs.push("a".to_string(), &[]).unwrap();
+        let b = gs.push("b".to_string(), &[a]).unwrap();
+        let d = gs.push("d".to_string(), &[b]).unwrap();
+        gs.push("c".to_string(), &[b, d]).unwrap();
+        gs
+    }
 
     #[test]
     fn check_iterator() {
         // a - b - c - e - f - g - h
         //      \ d -/------/
-        let mut gs = GraphStack::new();
+        let mut gs: GraphStack<&str> = GraphStack::new();
         let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e", "f", "g", "h"]
             .iter()
             .cloned()
-            .map(|value| (value, gs.push(value, &[])))
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
             .collect();
 
-        gs.add_ancestors(idmap["b"], &[idmap["a"]]);
-        gs.add_ancestors(idmap["c"], &[idmap["b"]]);
-        gs.add_ancestors(idmap["d"], &[idmap["b"]]);
-        gs.add_ancestors(idmap["e"], &[idmap["c"], idmap["d"]]);
-        gs.add_ancestors(idmap["f"], &[idmap["e"]]);
-        gs.add_ancestors(idmap["g"], &[idmap["d"], idmap["f"]]);
-        gs.add_ancestors(idmap["h"], &[idmap["g"]]);
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["d"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["e"], &[idmap["c"], idmap["d"]]).unwrap();
+        gs.add_ancestors(idmap["f"], &[idmap["e"]]).unwrap();
+        gs.add_ancestors(idmap["g"], &[idmap["d"], idmap["f"]]).unwrap();
+        gs.add_ancestors(idmap["h"], &[idmap["g"]]).unwrap();
         let mut it = gs.stacks(idmap["h"]);
         assert_eq!(it.next().unwrap(), vec![&"h", &"g", &"d", &"b", &"a"]);
         assert_eq!(
@@ -164,17 +882,17 @@ mod tests {
     fn disjoint_stacks() {
         // a - b - c
         // d - e
-        let mut gs = GraphStack::new();
+        let mut gs: GraphStack<&str> = GraphStack::new();
         let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e"]
             .iter()
             .cloned()
-            .map(|value| (value, gs.push(value, &[])))
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
             .collect();
 
-        gs.add_ancestors(idmap["b"], &[idmap["a"]]);
-        gs.add_ancestors(idmap["c"], &[idmap["b"]]);
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
         // disjoint stack
-        gs.add_ancestors(idmap["e"], &[idmap["d"]]);
+        gs.add_ancestors(idmap["e"], &[idmap["d"]]).unwrap();
 
         let mut it = gs.stacks(idmap["e"]);
         assert_eq!(it.next().unwrap(), vec![&"e", &"d"]);
@@ -189,16 +907,16 @@ mod tests {
     fn x_stack() {
         // a - b - c
         // d /  \ e
-        let mut gs = GraphStack::new();
+        let mut gs: GraphStack<&str> = GraphStack::new();
         let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e"]
             .iter()
             .cloned()
-            .map(|value| (value, gs.push(value, &[])))
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
             .collect();
 
-        gs.add_ancestors(idmap["b"], &[idmap["a"], idmap["d"]]);
-        gs.add_ancestors(idmap["c"], &[idmap["b"]]);
-        gs.add_ancestors(idmap["e"], &[idmap["b"]]);
+        gs.add_ancestors(idmap["b"], &[idmap["a"], idmap["d"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["e"], &[idmap["b"]]).unwrap();
 
         let mut it = gs.stacks(idmap["e"]);
         assert_eq!(it.next().unwrap(), vec![&"e", &"b", &"a"]);
@@ -216,7 +934,230 @@ mod tests {
         assert!(it.next().is_none());
     }
 
-    // TODO: test case for adding a cycle
-    // create a cycle
-    // gs.add_ancestors(idmap["a"], &[idmap["h"]]);
+    #[test]
+    fn ancestor_set_iteration() {
+        // a - b - c - e - f - g - h
+        //      \ d -/------/
+        let mut gs: GraphStack<&str> = GraphStack::new();
+        let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .cloned()
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
+            .collect();
+
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["d"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["e"], &[idmap["c"], idmap["d"]]).unwrap();
+        gs.add_ancestors(idmap["f"], &[idmap["e"]]).unwrap();
+        gs.add_ancestors(idmap["g"], &[idmap["d"], idmap["f"]]).unwrap();
+        gs.add_ancestors(idmap["h"], &[idmap["g"]]).unwrap();
+
+        // Exclusive: every ancestor of h, once, descending.
+        let got: Vec<_> = gs.ancestors(&[idmap["h"]], 0, false).collect();
+        assert_eq!(got, vec![6, 5, 4, 3, 2, 1, 0]);
+
+        // Inclusive surfaces the start first.
+        let got: Vec<_> = gs.ancestors(&[idmap["h"]], 0, true).collect();
+        assert_eq!(got, vec![7, 6, 5, 4, 3, 2, 1, 0]);
+
+        // The stop bound prunes traversal below the cutoff.
+        let got: Vec<_> = gs.ancestors(&[idmap["h"]], idmap["d"], false).collect();
+        assert_eq!(got, vec![6, 5, 4, 3]);
+    }
+
+    #[test]
+    fn missing_ancestors_skips_covered_branches() {
+        // a - b - c - e - f - g - h
+        //      \ d -/------/
+        let mut gs: GraphStack<&str> = GraphStack::new();
+        let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .cloned()
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
+            .collect();
+
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["d"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["e"], &[idmap["c"], idmap["d"]]).unwrap();
+        gs.add_ancestors(idmap["f"], &[idmap["e"]]).unwrap();
+        gs.add_ancestors(idmap["g"], &[idmap["d"], idmap["f"]]).unwrap();
+        gs.add_ancestors(idmap["h"], &[idmap["g"]]).unwrap();
+
+        // d covers {d, b, a}; the remaining ancestors of h are g, f, e, c.
+        let got = gs.missing_ancestors(&[idmap["d"]], idmap["h"]);
+        assert_eq!(
+            got,
+            vec![idmap["c"], idmap["e"], idmap["f"], idmap["g"]]
+        );
+
+        // With no bases, every ancestor of h is "missing".
+        let got = gs.missing_ancestors(&[], idmap["h"]);
+        assert_eq!(got, vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn dominators_and_nca() {
+        // a - b - c - e - f - g - h
+        //      \ d -/------/
+        let mut gs: GraphStack<&str> = GraphStack::new();
+        let idmap: HashMap<_, _> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .cloned()
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
+            .collect();
+
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["d"], &[idmap["b"]]).unwrap();
+        gs.add_ancestors(idmap["e"], &[idmap["c"], idmap["d"]]).unwrap();
+        gs.add_ancestors(idmap["f"], &[idmap["e"]]).unwrap();
+        gs.add_ancestors(idmap["g"], &[idmap["d"], idmap["f"]]).unwrap();
+        gs.add_ancestors(idmap["h"], &[idmap["g"]]).unwrap();
+
+        let idom = gs.dominators(idmap["h"]);
+        // Every path from h crosses g, so g dominates b, d and everything deeper.
+        assert_eq!(idom[&idmap["h"]], idmap["h"]);
+        assert_eq!(idom[&idmap["g"]], idmap["h"]);
+        assert_eq!(idom[&idmap["f"]], idmap["g"]);
+        assert_eq!(idom[&idmap["e"]], idmap["f"]);
+        assert_eq!(idom[&idmap["d"]], idmap["g"]);
+        assert_eq!(idom[&idmap["c"]], idmap["e"]);
+        assert_eq!(idom[&idmap["b"]], idmap["g"]);
+        assert_eq!(idom[&idmap["a"]], idmap["b"]);
+
+        // c and a merge back at g; since e dominates c, the NCA of c and e is
+        // e itself.
+        assert_eq!(
+            gs.nearest_common_ancestor(idmap["h"], idmap["c"], idmap["a"]),
+            Some(idmap["g"])
+        );
+        assert_eq!(
+            gs.nearest_common_ancestor(idmap["h"], idmap["c"], idmap["e"]),
+            Some(idmap["e"])
+        );
+    }
+
+    #[test]
+    fn condense_collapses_cycle() {
+        // 0 -> 1 -> 2 -> 0 forms one SCC; 3 -> 0 sits above it.
+        let mut gs: GraphStack<&str> = GraphStack::new();
+        for value in ["a", "b", "c", "d"] {
+            gs.push(value, &[]).unwrap();
+        }
+        // add_ancestors would reject the back-edge, so wire the cycle by hand.
+        gs.ancestors.insert(0, vec![(1, ())]);
+        gs.ancestors.insert(1, vec![(2, ())]);
+        gs.ancestors.insert(2, vec![(0, ())]);
+        gs.ancestors.insert(3, vec![(0, ())]);
+
+        let c = gs.condense();
+        assert_eq!(c.items.len(), 2);
+
+        // Component emitted first (a sink) is the 3-cycle, with no ancestors.
+        let mut cycle = c.items[0].clone();
+        cycle.sort_unstable();
+        assert_eq!(cycle, vec![0, 1, 2]);
+        assert!(c.ancestors[&0].is_empty());
+
+        // The second component is {3}, pointing at the cycle's component.
+        assert_eq!(c.items[1], vec![3]);
+        assert_eq!(c.ancestors[&1], vec![(0, ())]);
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let gs = sample();
+        let codec = Utf8Codec;
+
+        let mut buf = Vec::new();
+        let id = gs.serialize(&mut buf, &codec).unwrap();
+
+        let restored = GraphStack::<String>::deserialize(&mut buf.as_slice(), &codec).unwrap();
+        assert_eq!(restored.items, gs.items);
+        assert_eq!(restored.ancestors, gs.ancestors);
+
+        // Content id is stable across reload and matches the restored copy.
+        assert_eq!(restored.content_id(&codec), id);
+
+        // A structurally identical graph-stack yields the same content id,
+        // while a different element changes it.
+        assert_eq!(sample().content_id(&codec), id);
+        let mut other = sample();
+        other.items[0] = "z".to_string();
+        assert_ne!(other.content_id(&codec), id);
+    }
+
+    #[test]
+    fn deserialize_detects_corruption() {
+        let codec = Utf8Codec;
+        let mut buf = Vec::new();
+        sample().serialize(&mut buf, &codec).unwrap();
+        // Corrupt the stored content id so it no longer matches the payload.
+        buf[0] ^= 0xff;
+        match GraphStack::<String>::deserialize(&mut buf.as_slice(), &codec) {
+            Ok(_) => panic!("corrupted snapshot should not deserialize"),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+        }
+    }
+
+    #[test]
+    fn labeled_edges() {
+        // a - b - c, with each edge tagged by the symbol consumed.
+        // d /
+        let mut gs: GraphStack<&str, char> = GraphStack::new();
+        let a = gs.push_labeled("a", &[]).unwrap();
+        let d = gs.push_labeled("d", &[]).unwrap();
+        let b = gs.push_labeled("b", &[(a, 'x'), (d, 'y')]).unwrap();
+        let c = gs.push_labeled("c", &[(b, 'z')]).unwrap();
+
+        let mut it = gs.labeled_stacks(c);
+        assert_eq!(
+            it.next().unwrap(),
+            vec![(&"c", None), (&"b", Some(&'z')), (&"a", Some(&'x'))]
+        );
+        assert_eq!(
+            it.next().unwrap(),
+            vec![(&"c", None), (&"b", Some(&'z')), (&"d", Some(&'y'))]
+        );
+        assert!(it.next().is_none());
+
+        // The unlabeled iterator still yields plain node sequences.
+        let got: Vec<_> = gs.stacks(c).collect();
+        assert_eq!(got, vec![vec![&"c", &"b", &"a"], vec![&"c", &"b", &"d"]]);
+
+        // Labeled edges are still cycle-checked.
+        assert_eq!(
+            gs.add_ancestors_labeled(a, &[(c, 'w')]),
+            Err(GraphStackError::Cycle { id: a, ancestor: c })
+        );
+    }
+
+    #[test]
+    fn reject_cycle() {
+        // a - b - c, then try to close a -> c (c already reaches a)
+        let mut gs: GraphStack<&str> = GraphStack::new();
+        let idmap: HashMap<_, _> = ["a", "b", "c"]
+            .iter()
+            .cloned()
+            .map(|value| (value, gs.push(value, &[]).unwrap()))
+            .collect();
+
+        gs.add_ancestors(idmap["b"], &[idmap["a"]]).unwrap();
+        gs.add_ancestors(idmap["c"], &[idmap["b"]]).unwrap();
+
+        assert!(gs.has_path(idmap["c"], idmap["a"]));
+        assert!(!gs.has_path(idmap["a"], idmap["c"]));
+
+        assert_eq!(
+            gs.add_ancestors(idmap["a"], &[idmap["c"]]),
+            Err(GraphStackError::Cycle {
+                id: idmap["a"],
+                ancestor: idmap["c"],
+            })
+        );
+        // Rejected edge leaves the structure unchanged.
+        assert!(gs.ancestors[&idmap["a"]].is_empty());
+    }
 }